@@ -1,12 +1,125 @@
-#![feature(try_trait)]
-use std::{collections::HashMap, option::NoneError};
-use Command::*;
-use Operator::*;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::iter::Peekable;
+use std::ops::{Add, Div, Mul, Sub};
+use std::slice::Iter;
 
 pub type Value = i32;
 pub type ForthResult = Result<(), Error>;
 
-#[derive(Debug, PartialEq)]
+/// Handy definitions every `Forth::new()` starts out knowing, on top of the
+/// built-in stack words. `Forth::default()` does not load them.
+const PRELUDE: &str = ": square dup * ; : cube dup dup * * ; : f->c 32 - 5 * 9 / ; : c->f 9 * 5 / 32 + ;";
+
+/// The numeric backend a `Forth` stack operates over. Implemented for the
+/// built-in integer types below and, behind the `decimal` feature, for
+/// `rust_decimal::Decimal`, so the same interpreter can run on exact
+/// fixed-point values as well as plain integers.
+pub trait Num:
+  Copy + PartialEq + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self>
+{
+  fn zero() -> Self;
+  fn parse(input: &str) -> Option<Self>;
+  fn signum(self) -> Self;
+  fn checked_add(self, rhs: Self) -> Option<Self>;
+  fn checked_sub(self, rhs: Self) -> Option<Self>;
+  fn checked_mul(self, rhs: Self) -> Option<Self>;
+  fn checked_div(self, rhs: Self) -> Option<Self>;
+  /// Used by `pick`/`roll` to turn a stack depth into an index.
+  fn to_usize(self) -> Option<usize>;
+}
+
+impl Num for i32 {
+  fn zero() -> Self {
+    0
+  }
+  fn parse(input: &str) -> Option<Self> {
+    input.parse().ok()
+  }
+  fn signum(self) -> Self {
+    i32::signum(self)
+  }
+  fn checked_add(self, rhs: Self) -> Option<Self> {
+    i32::checked_add(self, rhs)
+  }
+  fn checked_sub(self, rhs: Self) -> Option<Self> {
+    i32::checked_sub(self, rhs)
+  }
+  fn checked_mul(self, rhs: Self) -> Option<Self> {
+    i32::checked_mul(self, rhs)
+  }
+  fn checked_div(self, rhs: Self) -> Option<Self> {
+    i32::checked_div(self, rhs)
+  }
+  fn to_usize(self) -> Option<usize> {
+    usize::try_from(self).ok()
+  }
+}
+
+impl Num for i64 {
+  fn zero() -> Self {
+    0
+  }
+  fn parse(input: &str) -> Option<Self> {
+    input.parse().ok()
+  }
+  fn signum(self) -> Self {
+    i64::signum(self)
+  }
+  fn checked_add(self, rhs: Self) -> Option<Self> {
+    i64::checked_add(self, rhs)
+  }
+  fn checked_sub(self, rhs: Self) -> Option<Self> {
+    i64::checked_sub(self, rhs)
+  }
+  fn checked_mul(self, rhs: Self) -> Option<Self> {
+    i64::checked_mul(self, rhs)
+  }
+  fn checked_div(self, rhs: Self) -> Option<Self> {
+    i64::checked_div(self, rhs)
+  }
+  fn to_usize(self) -> Option<usize> {
+    usize::try_from(self).ok()
+  }
+}
+
+#[cfg(feature = "decimal")]
+impl Num for rust_decimal::Decimal {
+  fn zero() -> Self {
+    rust_decimal::Decimal::ZERO
+  }
+  fn parse(input: &str) -> Option<Self> {
+    input.parse().ok()
+  }
+  fn signum(self) -> Self {
+    use std::cmp::Ordering;
+    match self.cmp(&rust_decimal::Decimal::ZERO) {
+      Ordering::Less => -rust_decimal::Decimal::ONE,
+      Ordering::Equal => rust_decimal::Decimal::ZERO,
+      Ordering::Greater => rust_decimal::Decimal::ONE,
+    }
+  }
+  fn checked_add(self, rhs: Self) -> Option<Self> {
+    rust_decimal::Decimal::checked_add(self, rhs)
+  }
+  fn checked_sub(self, rhs: Self) -> Option<Self> {
+    rust_decimal::Decimal::checked_sub(self, rhs)
+  }
+  fn checked_mul(self, rhs: Self) -> Option<Self> {
+    rust_decimal::Decimal::checked_mul(self, rhs)
+  }
+  fn checked_div(self, rhs: Self) -> Option<Self> {
+    rust_decimal::Decimal::checked_div(self, rhs)
+  }
+  fn to_usize(self) -> Option<usize> {
+    use num_traits::ToPrimitive;
+    self.to_u64().and_then(|value| usize::try_from(value).ok())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Operator {
   Plus,
   Minus,
@@ -14,13 +127,25 @@ enum Operator {
   Multiply,
 }
 
-#[derive(Debug)]
-enum Command {
-  Dropp, // collides with Rust keyword
-  Dup,
-  Swap,
-  Over,
-  Word((String, String)),
+#[derive(Debug, Clone, PartialEq)]
+enum Token<T> {
+  Number(T),
+  Op(Operator),
+  Ident(String),
+  Colon,
+  Semicolon,
+}
+
+#[derive(Debug, Clone)]
+enum Node<T> {
+  Push(T),
+  Op(Operator),
+  Call(String),
+  Define { name: String, body: Vec<Node<T>> },
+  Variable(String),
+  Constant(String),
+  Store(String),
+  Fetch(String),
 }
 
 #[derive(Debug, PartialEq)]
@@ -29,231 +154,622 @@ pub enum Error {
   StackUnderflow,
   UnknownWord,
   InvalidWord,
+  Overflow,
+  MismatchedParens,
+  UninitializedCell,
 }
 
-impl From<NoneError> for Error {
-  fn from(_error: NoneError) -> Error {
-    Error::StackUnderflow
-  }
+#[derive(Debug)]
+pub struct Forth<T: Num = Value> {
+  stack: Vec<T>,
+  words: HashMap<String, Vec<Node<T>>>,
+  registers: HashMap<String, Option<T>>,
 }
 
-#[derive(Default, Debug)]
-pub struct Forth {
-  stack: Vec<i32>,
-  words: HashMap<String, String>,
+impl<T: Num> Default for Forth<T> {
+  fn default() -> Self {
+    Forth {
+      stack: Vec::new(),
+      words: HashMap::new(),
+      registers: HashMap::new(),
+    }
+  }
 }
 
-impl Forth {
+impl<T: Num> Forth<T> {
   pub fn new() -> Self {
-    Forth::default()
+    let mut forth = Forth::default();
+    forth.eval(PRELUDE).expect("prelude is valid Forth");
+    forth
   }
-  pub fn stack(&self) -> Vec<Value> {
+
+  pub fn stack(&self) -> Vec<T> {
     self.stack.clone()
   }
 
-  fn filter_non_words(input: &str) -> String {
-    input.chars().fold(String::new(), |mut acc, chr| {
-      if chr.is_whitespace() || chr.is_control() {
-        acc = acc + &' '.to_string();
-        acc
-      } else {
-        acc = acc + &chr.to_string();
-        acc
-      }
-    })
+  pub fn eval(&mut self, input: &str) -> ForthResult {
+    let tokens = tokenize(input);
+    let mut words = self.words.keys().cloned().collect();
+    let ast = parse(&tokens, &mut words)?;
+    self.eval_nodes(&ast)
   }
 
-  pub fn eval<'a>(&'a mut self, input: &'a str) -> ForthResult {
-    let mut input = Self::filter_non_words(input);
-    while !input.is_empty() {
-      input = self.eval_digits(input);
-      input = self.eval_operators(input)?;
-      input = self.eval_word_declarations(input)?;
-      input = self.eval_word(&input)?;
-      input = self.eval_commands(input)?;
-    }
-    Ok(())
+  /// Evaluates ordinary infix arithmetic, e.g. `( 3 + 4 ) * 2`, by
+  /// reordering it into the postfix stream `eval` already understands
+  /// before running it against the stack.
+  pub fn eval_infix(&mut self, input: &str) -> ForthResult {
+    let infix_tokens = tokenize_infix(input);
+    let tokens = shunting_yard(infix_tokens)?;
+    let mut words = self.words.keys().cloned().collect();
+    let ast = parse(&tokens, &mut words)?;
+    self.eval_nodes(&ast)
   }
 
-  fn eval_digits(&mut self, mut input: String) -> String {
-    while let (Some(head), tail) = Self::parse_digit(input.clone()) {
-      self.stack.push(head);
-      input = tail.to_string();
-    }
-    input
-  }
-
-  fn eval_operators(&mut self, mut input: String) -> Result<String, Error> {
-    while let (Some(operator), tail) = Self::parse_operator(input.to_string()) {
-      let value2 = self.stack.pop()?;
-      let value1 = self.stack.pop()?;
-      match operator {
-        Plus => self.stack.push(value1 + value2),
-        Minus => self.stack.push(value1 - value2),
-        Divide => {
-          if value2 == 0 {
-            return Err(Error::DivisionByZero);
+  /// Runs an interactive session, evaluating one line of `input` at a
+  /// time. `stack`/`words`/`registers` persist across lines since they
+  /// live on `self`; a line that fails to evaluate is reported to
+  /// `output` without ending the session. `.` pops and prints the top of
+  /// the stack, `.s` prints the whole stack.
+  pub fn run_repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()>
+  where
+    T: fmt::Debug,
+  {
+    for line in input.lines() {
+      let line = line?;
+      let mut buffer = String::new();
+      for word in line.split_whitespace() {
+        match word {
+          "." => match self.flush(&mut buffer).and_then(|()| self.pop()) {
+            Ok(value) => writeln!(output, "{:?}", value)?,
+            Err(error) => writeln!(output, "error: {:?}", error)?,
+          },
+          ".s" => match self.flush(&mut buffer) {
+            Ok(()) => writeln!(output, "{:?}", self.stack())?,
+            Err(error) => writeln!(output, "error: {:?}", error)?,
+          },
+          _ => {
+            if !buffer.is_empty() {
+              buffer.push(' ');
+            }
+            buffer.push_str(word);
           }
-          self.stack.push(value1 / value2)
         }
-        Multiply => self.stack.push(value1 * value2),
       }
-      input = tail.to_string();
+      if let Err(error) = self.flush(&mut buffer) {
+        writeln!(output, "error: {:?}", error)?;
+      }
     }
-    Ok(input)
+    Ok(())
   }
 
-  fn eval_word_declarations(&mut self, mut input: String) -> Result<String, Error> {
-    while let (Some(Word((key, value))), tail) = Self::parse_word_delcaration(input.to_string())? {
-      self.words.insert(key, value);
-      input = tail.to_string()
+  // Evaluates whatever has accumulated since the last `.`/`.s`, so those
+  // words can appear inline (e.g. `3 dup * .`) and still see the values
+  // pushed earlier on the same line.
+  fn flush(&mut self, buffer: &mut String) -> ForthResult {
+    if buffer.is_empty() {
+      return Ok(());
     }
-    Ok(input)
+    let result = self.eval(buffer);
+    buffer.clear();
+    result
   }
 
-  fn eval_word(&mut self, input: &str) -> Result<String, Error> {
-    if let (Some(value), tail) = self.parse_word(input) {
-      return Ok(value + tail);
+  fn eval_nodes(&mut self, nodes: &[Node<T>]) -> ForthResult {
+    for node in nodes {
+      self.eval_node(node)?;
     }
-    Ok(input.to_string())
+    Ok(())
   }
 
-  fn eval_commands(&mut self, mut input: String) -> Result<String, Error> {
-    while let (Some(command), tail) = Self::parse_command(input.to_string())? {
-      match command {
-        Swap => {
-          let value2 = self.stack.pop()?;
-          let value1 = self.stack.pop()?;
-          self.stack.push(value2);
-          self.stack.push(value1);
-        }
-        Dropp => {
-          self.stack.pop()?;
-        }
-        Dup => {
-          let last = *(self.stack.iter().last()?);
-          self.stack.push(last);
-        }
-        Over => {
-          let value2 = self.stack.pop()?;
-          let value1 = self.stack.pop()?;
-          self.stack.push(value1);
-          self.stack.push(value2);
-          self.stack.push(value1);
-        }
-        Word((key, value)) => {
-          self.words.insert(key, value);
-        }
+  fn eval_node(&mut self, node: &Node<T>) -> ForthResult {
+    match node {
+      Node::Push(value) => self.stack.push(*value),
+      Node::Op(operator) => self.eval_operator(*operator)?,
+      Node::Define { name, body } => {
+        let body = self.resolve_body(body.clone());
+        self.words.insert(name.clone(), body);
       }
-      input = tail.to_string();
-    }
-    Ok(input)
-  }
-
-  fn parse_digit(input: String) -> (Option<Value>, String) {
-    match input.chars().position(|chr| chr.is_whitespace()) {
-      Some(position) => {
-        let head = &input[..position];
-        let tail = &input[position..];
-        if let Ok(value) = head.parse::<Value>() {
-          (Some(value), tail.trim_left().to_string())
-        } else {
-          (None, input.trim().to_string())
+      Node::Call(name) => self.eval_call(name)?,
+      Node::Variable(name) => {
+        self.registers.insert(name.clone(), None);
+      }
+      Node::Constant(name) => {
+        let value = self.pop()?;
+        self.words.insert(name.clone(), vec![Node::Push(value)]);
+      }
+      Node::Store(name) => {
+        let value = self.pop()?;
+        if !self.registers.contains_key(name) {
+          return Err(Error::UnknownWord);
         }
+        self.registers.insert(name.clone(), Some(value));
       }
-      _ => match input.parse::<Value>() {
-        Ok(value) => (Some(value), "".to_string()),
-        _ => (None, input),
+      Node::Fetch(name) => match self.registers.get(name) {
+        Some(Some(value)) => self.stack.push(*value),
+        Some(None) => return Err(Error::UninitializedCell),
+        None => return Err(Error::UnknownWord),
       },
     }
+    Ok(())
   }
 
-  fn parse_operator(input: String) -> (Option<Operator>, String) {
-    if input.is_empty() {
-      return (None, "".to_string());
-    }
-    let head = &input[..1];
-    let tail = &input[1..].trim_left();
-    match head {
-      "+" => (Some(Plus), tail.to_string()),
-      "-" => (Some(Minus), tail.to_string()),
-      "/" => (Some(Divide), tail.to_string()),
-      "*" => (Some(Multiply), tail.to_string()),
-      _ => (None, input.to_string()),
-    }
+  fn eval_operator(&mut self, operator: Operator) -> ForthResult {
+    let value2 = self.pop()?;
+    let value1 = self.pop()?;
+    let result = match operator {
+      Operator::Plus => value1.checked_add(value2).ok_or(Error::Overflow)?,
+      Operator::Minus => value1.checked_sub(value2).ok_or(Error::Overflow)?,
+      Operator::Multiply => value1.checked_mul(value2).ok_or(Error::Overflow)?,
+      Operator::Divide => {
+        if value2 == T::zero() {
+          return Err(Error::DivisionByZero);
+        }
+        value1.checked_div(value2).ok_or(Error::Overflow)?
+      }
+    };
+    self.stack.push(result);
+    Ok(())
   }
 
-  fn parse_command(input: String) -> Result<(Option<Command>, String), Error> {
-    if input.is_empty() {
-      return Ok((None, "".to_string()));
+  fn eval_call(&mut self, name: &str) -> ForthResult {
+    if let Some(body) = self.words.get(name).cloned() {
+      return self.eval_nodes(&body);
     }
-    let (head, tail) = match input.chars().position(|chr| chr.is_whitespace()) {
-      Some(position) => {
-        let head = input[..position].to_lowercase();
-        let tail = input[position..].trim_left();
-        (head, tail)
+    match name {
+      "drop" => {
+        self.pop()?;
       }
-      None => (input.to_string().to_lowercase(), ""),
-    };
-    match head.as_str() {
-      "drop" => Ok((Some(Dropp), tail.to_string())),
-      "dup" => Ok((Some(Dup), tail.to_string())),
-      "swap" => Ok((Some(Swap), tail.to_string())),
-      "over" => Ok((Some(Over), tail.to_string())),
-      digits if digits.parse::<u32>().is_ok() => Ok((None, "".to_string())),
-      _ => Err(Error::UnknownWord),
+      "dup" => {
+        let last = *self.stack.last().ok_or(Error::StackUnderflow)?;
+        self.stack.push(last);
+      }
+      "swap" => {
+        let value2 = self.pop()?;
+        let value1 = self.pop()?;
+        self.stack.push(value2);
+        self.stack.push(value1);
+      }
+      "over" => {
+        let value2 = self.pop()?;
+        let value1 = self.pop()?;
+        self.stack.push(value1);
+        self.stack.push(value2);
+        self.stack.push(value1);
+      }
+      "rot" => {
+        let c = self.pop()?;
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.stack.push(b);
+        self.stack.push(c);
+        self.stack.push(a);
+      }
+      "-rot" => {
+        let c = self.pop()?;
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.stack.push(c);
+        self.stack.push(a);
+        self.stack.push(b);
+      }
+      "nip" => {
+        let b = self.pop()?;
+        self.pop()?;
+        self.stack.push(b);
+      }
+      "tuck" => {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.stack.push(b);
+        self.stack.push(a);
+        self.stack.push(b);
+      }
+      "pick" => {
+        let index = self.pick_index()?;
+        let value = *self.stack.get(index).ok_or(Error::StackUnderflow)?;
+        self.stack.push(value);
+      }
+      "roll" => {
+        let index = self.pick_index()?;
+        let value = self.stack.remove(index);
+        self.stack.push(value);
+      }
+      _ => return Err(Error::UnknownWord),
     }
+    Ok(())
+  }
+
+  // Pops the depth argument for `pick`/`roll` and turns it into a stack
+  // index counted from the bottom, so `0 pick` refers to the new top.
+  fn pick_index(&mut self) -> Result<usize, Error> {
+    let depth = self.pop()?.to_usize().ok_or(Error::InvalidWord)?;
+    depth
+      .checked_add(1)
+      .and_then(|depth_from_top| self.stack.len().checked_sub(depth_from_top))
+      .ok_or(Error::StackUnderflow)
+  }
+
+  // A word's body is resolved against the words that exist at definition
+  // time, so redefining a user word later doesn't change what earlier
+  // definitions do when they call it. Calls to names that are not user
+  // words at that point (built-ins such as `dup`, or words not defined
+  // yet) stay calls looked up at run time, so a later definition of such
+  // a name does take effect in earlier definitions.
+  fn resolve_body(&self, nodes: Vec<Node<T>>) -> Vec<Node<T>> {
+    nodes
+      .into_iter()
+      .flat_map(|node| match node {
+        Node::Call(ref name) => match self.words.get(name) {
+          Some(existing) => existing.clone(),
+          None => vec![node],
+        },
+        other => vec![other],
+      })
+      .collect()
+  }
+
+  fn pop(&mut self) -> Result<T, Error> {
+    self.stack.pop().ok_or(Error::StackUnderflow)
+  }
+}
+
+fn tokenize<T: Num>(input: &str) -> Vec<Token<T>> {
+  input
+    .split_whitespace()
+    .map(|word| match word {
+      "+" => Token::Op(Operator::Plus),
+      "-" => Token::Op(Operator::Minus),
+      "*" => Token::Op(Operator::Multiply),
+      "/" => Token::Op(Operator::Divide),
+      ":" => Token::Colon,
+      ";" => Token::Semicolon,
+      _ => match T::parse(word) {
+        Some(value) => Token::Number(value),
+        None => Token::Ident(word.to_lowercase()),
+      },
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum InfixToken<T> {
+  Number(T),
+  Op(Operator),
+  Ident(String),
+  LParen,
+  RParen,
+}
+
+fn tokenize_infix<T: Num>(input: &str) -> Vec<InfixToken<T>> {
+  input
+    .split_whitespace()
+    .map(|word| match word {
+      "+" => InfixToken::Op(Operator::Plus),
+      "-" => InfixToken::Op(Operator::Minus),
+      "*" => InfixToken::Op(Operator::Multiply),
+      "/" => InfixToken::Op(Operator::Divide),
+      "(" => InfixToken::LParen,
+      ")" => InfixToken::RParen,
+      _ => match T::parse(word) {
+        Some(value) => InfixToken::Number(value),
+        None => InfixToken::Ident(word.to_lowercase()),
+      },
+    })
+    .collect()
+}
+
+fn precedence(operator: Operator) -> u8 {
+  match operator {
+    Operator::Plus | Operator::Minus => 1,
+    Operator::Multiply | Operator::Divide => 2,
   }
+}
+
+enum StackItem {
+  Op(Operator),
+  LParen,
+}
 
-  fn parse_word_delcaration(input: String) -> Result<(Option<Command>, String), Error> {
-    if input.is_empty() || input.chars().nth(0).unwrap() != ':' {
-      return Ok((None, "".to_string()));
+// Dijkstra's shunting-yard: numbers and identifiers go straight to the
+// output queue, operators are held back until everything of equal or
+// higher precedence already on the stack has been flushed, and
+// parentheses just bracket when that flushing happens.
+fn shunting_yard<T: Num>(tokens: Vec<InfixToken<T>>) -> Result<Vec<Token<T>>, Error> {
+  let mut output = Vec::new();
+  let mut operators: Vec<StackItem> = Vec::new();
+
+  for token in tokens {
+    match token {
+      InfixToken::Number(value) => output.push(Token::Number(value)),
+      InfixToken::Ident(name) => output.push(Token::Ident(name)),
+      InfixToken::Op(operator) => {
+        while let Some(StackItem::Op(top)) = operators.last() {
+          if precedence(*top) < precedence(operator) {
+            break;
+          }
+          match operators.pop() {
+            Some(StackItem::Op(top)) => output.push(Token::Op(top)),
+            _ => unreachable!(),
+          }
+        }
+        operators.push(StackItem::Op(operator));
+      }
+      InfixToken::LParen => operators.push(StackItem::LParen),
+      InfixToken::RParen => loop {
+        match operators.pop() {
+          Some(StackItem::Op(operator)) => output.push(Token::Op(operator)),
+          Some(StackItem::LParen) => break,
+          None => return Err(Error::MismatchedParens),
+        }
+      },
     }
-    let body = input
-      .chars()
-      .skip(1)
-      .take_while(|&chr| chr != ';')
-      .collect::<String>()
-      .trim()
-      .to_string();
-    let rest = input
-      .chars()
-      .skip_while(|&chr| chr != ';')
-      .skip(1)
-      .collect::<String>()
-      .trim()
-      .to_string();
-
-    let key: String = body.chars().take_while(|&chr| chr != ' ').collect();
-    let value: String = body.chars().skip_while(|&chr| chr != ' ').skip(1).collect();
-
-    let contains_terminator = input.chars().any(|chr| chr == ';');
-    if !contains_terminator || body.is_empty() || value.is_empty() {
-      return Err(Error::InvalidWord);
+  }
+
+  while let Some(item) = operators.pop() {
+    match item {
+      StackItem::Op(operator) => output.push(Token::Op(operator)),
+      StackItem::LParen => return Err(Error::MismatchedParens),
     }
+  }
 
-    match key.chars().nth(0) {
-      Some(first_digit) => if first_digit.is_numeric() {
-        return Err(Error::InvalidWord);
+  Ok(output)
+}
+
+// `words` is the set of words defined so far, seeded from the words already
+// known to the interpreter and grown as `:`...`;` definitions are parsed, so
+// a user definition named "variable" or "constant" takes precedence over the
+// built-in keyword as soon as it's defined — matching how every other
+// built-in word can be overridden by redefining it (see `eval_call`).
+fn parse<T: Num>(tokens: &[Token<T>], words: &mut HashSet<String>) -> Result<Vec<Node<T>>, Error> {
+  let mut nodes = Vec::new();
+  let mut iter = tokens.iter().peekable();
+  while let Some(token) = iter.next() {
+    match token {
+      Token::Number(value) => nodes.push(Node::Push(*value)),
+      Token::Op(operator) => nodes.push(Node::Op(*operator)),
+      Token::Colon => nodes.push(parse_definition(&mut iter, words)?),
+      Token::Semicolon => return Err(Error::InvalidWord),
+      Token::Ident(name) if name == "variable" && !words.contains(name) => {
+        nodes.push(Node::Variable(expect_name(&mut iter)?));
+      }
+      Token::Ident(name) if name == "constant" && !words.contains(name) => {
+        nodes.push(Node::Constant(expect_name(&mut iter)?));
+      }
+      Token::Ident(name) => match iter.peek() {
+        Some(Token::Ident(next)) if next == "!" => {
+          iter.next();
+          nodes.push(Node::Store(name.clone()));
+        }
+        Some(Token::Ident(next)) if next == "@" => {
+          iter.next();
+          nodes.push(Node::Fetch(name.clone()));
+        }
+        _ => nodes.push(Node::Call(name.clone())),
       },
-      None => return Err(Error::InvalidWord),
     }
+  }
+  Ok(nodes)
+}
 
-    Ok((Some(Word((key.to_lowercase(), value))), rest))
+fn expect_name<T: Num>(iter: &mut Peekable<Iter<Token<T>>>) -> Result<String, Error> {
+  match iter.next() {
+    Some(Token::Ident(name)) => Ok(name.clone()),
+    _ => Err(Error::InvalidWord),
   }
+}
 
-  fn parse_word<'a>(&self, input: &'a str) -> (Option<String>, &'a str) {
-    let (head, tail) = match input.chars().position(|chr| chr.is_whitespace()) {
-      Some(position) => {
-        let head = &input[..position];
-        let tail = &input[position..];
-        (head, tail)
+fn parse_definition<T: Num>(
+  iter: &mut Peekable<Iter<Token<T>>>,
+  words: &mut HashSet<String>,
+) -> Result<Node<T>, Error> {
+  let name = match iter.next() {
+    Some(Token::Ident(name)) => {
+      if name.chars().next().is_none_or(|chr| chr.is_numeric()) {
+        return Err(Error::InvalidWord);
       }
-      None => (input, ""),
-    };
-    match self.words.get(&head.to_lowercase()) {
-      Some(value) => (Some(value.to_string() + tail), ""),
-      None => (None, input),
+      name.clone()
+    }
+    _ => return Err(Error::InvalidWord),
+  };
+
+  let mut body_tokens = Vec::new();
+  loop {
+    match iter.next() {
+      Some(Token::Semicolon) => break,
+      Some(Token::Colon) => return Err(Error::InvalidWord),
+      Some(token) => body_tokens.push(token.clone()),
+      None => return Err(Error::InvalidWord),
     }
   }
-}
\ No newline at end of file
+
+  if body_tokens.is_empty() {
+    return Err(Error::InvalidWord);
+  }
+
+  let body = parse(&body_tokens, words)?;
+  words.insert(name.clone());
+  Ok(Node::Define { name, body })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn evaluates_basic_arithmetic() {
+    let mut forth: Forth = Forth::new();
+    assert_eq!(forth.eval("1 2 + 4 *"), Ok(()));
+    assert_eq!(forth.stack(), vec![12]);
+  }
+
+  #[test]
+  fn division_by_zero_is_an_error() {
+    let mut forth: Forth = Forth::new();
+    assert_eq!(forth.eval("4 0 /"), Err(Error::DivisionByZero));
+  }
+
+  #[test]
+  fn unknown_words_are_an_error() {
+    let mut forth: Forth = Forth::new();
+    assert_eq!(forth.eval("foo"), Err(Error::UnknownWord));
+  }
+
+  #[test]
+  fn user_defined_words_can_use_different_words_with_the_same_name() {
+    let mut forth: Forth = Forth::new();
+    forth.eval(": foo 5 ; : bar foo ; : foo 6 ; bar").unwrap();
+    assert_eq!(forth.stack(), vec![5]);
+  }
+
+  #[test]
+  fn user_defined_words_can_override_built_in_words() {
+    let mut forth: Forth = Forth::new();
+    forth.eval(": swap dup ; 1 swap").unwrap();
+    assert_eq!(forth.stack(), vec![1, 1]);
+  }
+
+  #[test]
+  fn user_defined_words_can_reference_themselves_by_name() {
+    let mut forth: Forth = Forth::new();
+    forth.eval(": foo 10 ; : foo foo 1 + ; foo").unwrap();
+    assert_eq!(forth.stack(), vec![11]);
+  }
+
+  #[test]
+  fn addition_overflow_is_an_error() {
+    let mut forth: Forth = Forth::new();
+    assert_eq!(forth.eval("2000000000 2000000000 +"), Err(Error::Overflow));
+  }
+
+  #[test]
+  fn division_overflow_is_an_error_not_a_panic() {
+    let mut forth: Forth = Forth::new();
+    assert_eq!(forth.eval("-2147483648 -1 /"), Err(Error::Overflow));
+  }
+
+  #[test]
+  fn eval_infix_respects_precedence_and_parens() {
+    let mut forth: Forth = Forth::new();
+    forth.eval_infix("( 3 + 4 ) * 2").unwrap();
+    assert_eq!(forth.stack(), vec![14]);
+  }
+
+  #[test]
+  fn eval_infix_reports_mismatched_parens() {
+    let mut forth: Forth = Forth::new();
+    assert_eq!(forth.eval_infix("( 3 + 4"), Err(Error::MismatchedParens));
+  }
+
+  #[test]
+  fn variables_store_and_fetch() {
+    let mut forth: Forth = Forth::new();
+    forth.eval("variable x 42 x ! x @").unwrap();
+    assert_eq!(forth.stack(), vec![42]);
+  }
+
+  #[test]
+  fn fetching_an_uninitialized_variable_is_an_error() {
+    let mut forth: Forth = Forth::new();
+    assert_eq!(forth.eval("variable x x @"), Err(Error::UninitializedCell));
+  }
+
+  #[test]
+  fn fetching_an_unknown_name_is_an_error() {
+    let mut forth: Forth = Forth::new();
+    assert_eq!(forth.eval("x @"), Err(Error::UnknownWord));
+  }
+
+  #[test]
+  fn constants_push_their_captured_value() {
+    let mut forth: Forth = Forth::new();
+    forth.eval("10 constant ten ten ten +").unwrap();
+    assert_eq!(forth.stack(), vec![20]);
+  }
+
+  #[test]
+  fn user_defined_words_can_override_variable_and_constant() {
+    let mut forth: Forth = Forth::new();
+    forth.eval(": variable dup dup ; 5 variable").unwrap();
+    assert_eq!(forth.stack(), vec![5, 5, 5]);
+
+    let mut forth: Forth = Forth::new();
+    forth.eval(": constant dup + ; 5 constant").unwrap();
+    assert_eq!(forth.stack(), vec![10]);
+  }
+
+  #[test]
+  fn stack_shuffling_words() {
+    let mut forth: Forth = Forth::new();
+    forth.eval("1 2 3 rot").unwrap();
+    assert_eq!(forth.stack(), vec![2, 3, 1]);
+
+    let mut forth: Forth = Forth::new();
+    forth.eval("1 2 3 -rot").unwrap();
+    assert_eq!(forth.stack(), vec![3, 1, 2]);
+
+    let mut forth: Forth = Forth::new();
+    forth.eval("1 2 nip").unwrap();
+    assert_eq!(forth.stack(), vec![2]);
+
+    let mut forth: Forth = Forth::new();
+    forth.eval("1 2 tuck").unwrap();
+    assert_eq!(forth.stack(), vec![2, 1, 2]);
+
+    let mut forth: Forth = Forth::new();
+    forth.eval("1 2 3 2 pick").unwrap();
+    assert_eq!(forth.stack(), vec![1, 2, 3, 1]);
+
+    let mut forth: Forth = Forth::new();
+    forth.eval("1 2 3 2 roll").unwrap();
+    assert_eq!(forth.stack(), vec![2, 3, 1]);
+  }
+
+  #[test]
+  fn prelude_words_are_available_without_definition() {
+    let mut forth: Forth = Forth::new();
+    forth.eval("5 square").unwrap();
+    assert_eq!(forth.stack(), vec![25]);
+
+    let mut forth: Forth = Forth::new();
+    forth.eval("3 cube").unwrap();
+    assert_eq!(forth.stack(), vec![27]);
+  }
+
+  // A depth of exactly `usize::MAX` doesn't fit an i32/i64 backend, but a
+  // Decimal literal can carry it; this is what used to panic on the
+  // `depth + 1` overflow inside `pick_index`.
+  #[cfg(feature = "decimal")]
+  #[test]
+  fn pick_with_a_depth_of_usize_max_is_a_stack_underflow_not_a_panic() {
+    let mut forth: Forth<rust_decimal::Decimal> = Forth::new();
+    let input = format!("1 2 3 {} pick", usize::MAX);
+    assert_eq!(forth.eval(&input), Err(Error::StackUnderflow));
+  }
+
+  #[test]
+  fn run_repl_keeps_state_across_lines_and_reports_errors() {
+    let mut forth: Forth = Forth::new();
+    let input = b"3 dup * .\n1 2 3\n.s\nbogus\n" as &[u8];
+    let mut output = Vec::new();
+    forth.run_repl(input, &mut output).unwrap();
+    let text = String::from_utf8(output).unwrap();
+    assert!(text.contains("9"));
+    assert!(text.contains("[1, 2, 3]"));
+    assert!(text.contains("error"));
+  }
+
+  #[test]
+  fn forth_i64_runs_the_same_programs_as_the_default_i32_backend() {
+    let mut forth: Forth<i64> = Forth::new();
+    forth.eval("3 4 +").unwrap();
+    assert_eq!(forth.stack(), vec![7i64]);
+  }
+
+  #[cfg(feature = "decimal")]
+  #[test]
+  fn forth_decimal_keeps_fractional_precision_that_i32_would_truncate() {
+    let mut forth: Forth<rust_decimal::Decimal> = Forth::new();
+    forth.eval("3 2 /").unwrap();
+    assert_eq!(forth.stack(), vec!["1.5".parse().unwrap()]);
+  }
+}
@@ -0,0 +1,7 @@
+use forth::Forth;
+use std::io;
+
+fn main() -> io::Result<()> {
+  let mut forth: Forth = Forth::new();
+  forth.run_repl(io::stdin().lock(), io::stdout())
+}